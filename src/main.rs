@@ -16,33 +16,135 @@ use rand::{thread_rng, Rng};
 use std::fmt::Debug;
 use std::fs::read_to_string;
 use std::net::{IpAddr, Ipv4Addr};
+use std::num::ParseIntError;
 use std::str::FromStr;
-use std::time::Instant;
-use tracing::info;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
 use virt::connect::Connect;
 use virt::domain::Domain;
 use virt::sys;
 
+/// How much time GCP gives a preempted VM before it is stopped
+const PREEMPTION_NOTICE: Duration = Duration::from_secs(30);
+/// How often the progress of a running migration is reported
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// How often aborting a migration that ran past its deadline is retried
+const ABORT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+const SOURCE_URI: &str = "qemu:///session";
+const DOMAIN_NAME: &str = "example-vm";
+
 #[tokio::main(worker_threads = 2)]
 async fn main() {
     dotenv().unwrap();
     tracing_subscriber::fmt::init();
+    // Preempted GCP VMs are stopped, not deleted, so this machine may have been preempted before and
+    // its domain may already live on another machine. Migrating it again would create a duplicate
+    if !is_domain_defined(SOURCE_URI, DOMAIN_NAME) {
+        info!(
+            "Domain '{}' is not defined on this machine anymore, it was most likely migrated away before",
+            DOMAIN_NAME
+        );
+        return;
+    }
     info!("Migration starting... Requesting new machine to be started...");
     let start = Instant::now();
-    let ip_address = create_instance_with_image().await;
-    migrate(
-        Some("qemu:///session".into()),
+    let provisioning_timeout =
+        phase_timeout("PROVISIONING_TIMEOUT_SECS", PREEMPTION_NOTICE * 2 / 3);
+    // Read all tunables before provisioning, so a typo does not leave an orphaned target behind
+    let migration_timeout = duration_from_env("MIGRATION_TIMEOUT_SECS");
+    let postcopy_after = duration_from_env("POSTCOPY_AFTER_SECS");
+    let max_bandwidth = u64_from_env("MIGRATION_MAX_BANDWIDTH_MIB").unwrap_or(0);
+    if postcopy_after.is_some() {
+        warn!(
+            "Post-copy migrations can not be tunnelled through libvirt, so the guest memory will be \
+             sent to the target unencrypted"
+        );
+    }
+    let provisioning = async {
+        match std::env::var("PROVIDER").as_deref() {
+            Ok("libvirt") => LibvirtProvider::from_env().create_instance().await,
+            _ => create_instance_with_image().await,
+        }
+    };
+    let Ok(ip_address) = tokio::time::timeout(provisioning_timeout, provisioning).await else {
+        exit_with_error(format!(
+            "Provisioning of the target machine timed out after {:?}",
+            provisioning_timeout
+        ));
+    };
+    let migration_options = MigrationOptions {
+        // Whatever provisioning left of the preemption notice is the time we have for migrating
+        timeout: migration_timeout
+            .unwrap_or_else(|| PREEMPTION_NOTICE.saturating_sub(start.elapsed())),
+        postcopy_after,
+        migration_uri: postcopy_after.map(|_| format!("tcp://{}", ip_address)),
+        max_bandwidth,
+    };
+    let outcome = migrate(
+        Some(SOURCE_URI.into()),
         Some(format!("ssh+qemu://{}/session", ip_address)),
-        "example-vm",
+        DOMAIN_NAME,
+        migration_options,
     );
     let duration = start.elapsed();
+    match outcome {
+        MigrationOutcome::Migrated => {}
+        MigrationOutcome::NotFound => exit_with_error(format!(
+            "Domain '{}' was not found on this machine, nothing was migrated",
+            DOMAIN_NAME
+        )),
+        MigrationOutcome::Failed => exit_with_error(format!(
+            "Migration failed after {:?} and was cancelled, domain '{}' should still be running on \
+             this machine",
+            duration, DOMAIN_NAME
+        )),
+        MigrationOutcome::FailedInPostCopy => exit_with_error(format!(
+            "Migration failed after {:?} in post-copy, domain '{}' is paused on both machines and \
+             has to be recovered with `virsh migrate --postcopy-resume`",
+            duration, DOMAIN_NAME
+        )),
+    }
     info!(
-        "Migration completed in {:?}. Time left: {} seconds",
+        "Migration completed in {:?}. Time left: {:?}",
         duration,
-        30 - duration.as_secs()
+        PREEMPTION_NOTICE.saturating_sub(duration)
     );
 }
 
+/// Logs why the migration failed and exits with a non-zero status, so whatever runs us on
+/// preemption can tell that the domain was not migrated
+fn exit_with_error(message: String) -> ! {
+    error!("{}", message);
+    std::process::exit(1);
+}
+
+/// Reads the timeout of a migration phase from the environment, falling back to `default`
+fn phase_timeout(var: &str, default: Duration) -> Duration {
+    duration_from_env(var).unwrap_or(default)
+}
+
+/// Reads a duration given in seconds from the environment
+fn duration_from_env(var: &str) -> Option<Duration> {
+    parse_from_env(var, parse_secs)
+}
+
+fn u64_from_env(var: &str) -> Option<u64> {
+    parse_from_env(var, |value| value.trim().parse())
+}
+
+/// Reads and parses an environment variable, panicking if it is set but invalid
+fn parse_from_env<T>(var: &str, parse: impl Fn(&str) -> Result<T, ParseIntError>) -> Option<T> {
+    std::env::var(var)
+        .ok()
+        .map(|value| parse(&value).unwrap_or_else(|_| panic!("Failed to parse {}: {}", var, value)))
+}
+
+fn parse_secs(secs: &str) -> Result<Duration, ParseIntError> {
+    secs.trim().parse().map(Duration::from_secs)
+}
+
 async fn get_zone() -> Result<String, reqwest::Error> {
     let client = reqwest::Client::new();
     let response = client
@@ -177,6 +279,68 @@ async fn create_instance_with_image() -> IpAddr {
     );
 }
 
+/// Uses an already defined domain on a (local or on-prem) libvirt host as the migration target,
+/// so migrations can be tried out on a workstation without any cloud credentials.
+struct LibvirtProvider {
+    uri: String,
+    domain: String,
+}
+
+impl LibvirtProvider {
+    fn from_env() -> Self {
+        Self {
+            uri: std::env::var("LIBVIRT_TARGET_URI").unwrap_or_else(|_| "qemu:///system".into()),
+            domain: std::env::var("LIBVIRT_TARGET_DOMAIN").expect(
+                "LIBVIRT_TARGET_DOMAIN not found in environment. Please provide the target domain",
+            ),
+        }
+    }
+
+    /// Starts the target domain if it is not running yet and waits until it got an ip address
+    async fn create_instance(&self) -> IpAddr {
+        info!("Starting domain '{}' on '{}'", self.domain, self.uri);
+        let mut conn = match Connect::open(Some(&self.uri)) {
+            Ok(c) => c,
+            Err(e) => panic!("No connection to target hypervisor: {}", e),
+        };
+        let dom = Domain::lookup_by_name(&conn, &self.domain)
+            .unwrap_or_else(|e| panic!("Failed to find domain '{}': {}", self.domain, e));
+        if !dom.is_active().unwrap() {
+            dom.create().unwrap();
+        }
+        // DHCP leases are only known on libvirt managed networks. On bridged networks the address
+        // can only be found in the ARP table or asked from the qemu guest agent
+        let sources = [
+            sys::VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_LEASE,
+            sys::VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_ARP,
+            sys::VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_AGENT,
+        ];
+        let ip_address = loop {
+            // The address only shows up once the guest configured its network, so we have to poll
+            let address = sources.iter().find_map(|&source| {
+                dom.interface_addresses(source, 0)
+                    .ok()
+                    .and_then(|interfaces| {
+                        interfaces
+                            .into_iter()
+                            .flat_map(|interface| interface.addrs)
+                            .find_map(|address| Ipv4Addr::from_str(&address.addr).ok())
+                    })
+            });
+            if let Some(address) = address {
+                break address;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        };
+        info!("Domain ip: {}", ip_address);
+
+        if let Err(e) = conn.close() {
+            panic!("Failed to disconnect from hypervisor: {}", e);
+        }
+        IpAddr::V4(ip_address)
+    }
+}
+
 fn get_ssh_key() -> String {
     let home_path =
         std::env::var("HOME").expect("HOME not found in environment. Please provide a home path");
@@ -187,7 +351,53 @@ fn get_ssh_key() -> String {
         .unwrap_or_else(|| panic!("Failed to read ssh key"))
 }
 
-fn migrate(src_uri: Option<String>, dst_uri: Option<String>, dname: &str) {
+fn is_domain_defined(uri: &str, dname: &str) -> bool {
+    let mut conn = match Connect::open(Some(uri)) {
+        Ok(c) => c,
+        Err(e) => panic!("No connection to source hypervisor: {}", e),
+    };
+    let defined = Domain::lookup_by_name(&conn, dname).is_ok();
+    if let Err(e) = conn.close() {
+        panic!("Failed to disconnect from hypervisor: {}", e);
+    }
+    defined
+}
+
+struct MigrationOptions {
+    /// The migration is aborted if it did not finish within this time
+    timeout: Duration,
+    /// Switch to post-copy after this time, so the domain already runs on the target while the
+    /// remaining memory pages are fetched from the source on demand
+    postcopy_after: Option<Duration>,
+    /// Where QEMU sends the memory to if the migration is not tunnelled through libvirt. The
+    /// libvirt connection to the target still goes to `dst_uri`
+    migration_uri: Option<String>,
+    /// Maximum bandwidth of the migration in MiB/s, so other guests on this machine still get
+    /// their share of the network. 0 means unlimited. This only limits the pre-copy phase, after
+    /// switching to post-copy the remaining memory is transferred without a limit
+    max_bandwidth: u64,
+}
+
+enum MigrationOutcome {
+    /// The domain is running on the target
+    Migrated,
+    /// There is no domain with that name on the source
+    NotFound,
+    /// libvirt cancelled the migration and resumed the domain on the source
+    Failed,
+    /// The migration failed after switching to post-copy. Neither machine has the whole state of
+    /// the domain anymore, so it stays paused on both until the migration is recovered
+    FailedInPostCopy,
+}
+
+/// Live migrates the domain. If the migration fails before switching to post-copy, libvirt cancels
+/// it and resumes the domain on the source
+fn migrate(
+    src_uri: Option<String>,
+    dst_uri: Option<String>,
+    dname: &str,
+    options: MigrationOptions,
+) -> MigrationOutcome {
     println!(
         "Attempting to migrate domain '{}' from '{:?}' to '{:?}'...",
         dname, src_uri, dst_uri
@@ -198,30 +408,154 @@ fn migrate(src_uri: Option<String>, dst_uri: Option<String>, dname: &str) {
         Err(e) => panic!("No connection to source hypervisor: {}", e),
     };
 
+    let mut outcome = MigrationOutcome::Failed;
     if let Ok(dom) = Domain::lookup_by_name(&conn, &dname) {
-        let flags = sys::VIR_MIGRATE_LIVE | sys::VIR_MIGRATE_PEER2PEER | sys::VIR_MIGRATE_TUNNELLED;
-        if dom
-            .migrate(&conn, flags, None, dst_uri.as_deref(), 0)
-            .is_ok()
-        {
+        // Move the definition along with the domain so that it is not started again here if this
+        // machine gets restarted after being preempted
+        let mut flags = sys::VIR_MIGRATE_LIVE
+            | sys::VIR_MIGRATE_PEER2PEER
+            | sys::VIR_MIGRATE_PERSIST_DEST
+            | sys::VIR_MIGRATE_UNDEFINE_SOURCE;
+        // libvirt can't tunnel post-copy migrations, so then QEMU sends the memory directly to
+        // the target. This needs its migration ports (49152-49215) to be reachable and the memory
+        // is not encrypted by the ssh connection anymore
+        if options.postcopy_after.is_some() {
+            flags |= sys::VIR_MIGRATE_POSTCOPY;
+        } else {
+            flags |= sys::VIR_MIGRATE_TUNNELLED;
+        }
+        let timeout = options.timeout;
+        let (migration_done, finished) = mpsc::channel::<()>();
+        let watchdog = {
+            let src_uri = src_uri.clone();
+            let dname = dname.to_string();
+            thread::spawn(move || {
+                let deadline = Instant::now() + timeout;
+                let mut postcopy_at = options.postcopy_after.map(|after| Instant::now() + after);
+                // The migration call blocks until it is done, so the job has to be watched from a
+                // second connection
+                let mut conn = match Connect::open(src_uri.as_deref()) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        println!("Failed to watch migration: {}", e);
+                        return WatchdogReport::default();
+                    }
+                };
+                // If the migration already finished the source domain is gone, so there is
+                // nothing left to watch
+                let Ok(dom) = Domain::lookup_by_name(&conn, &dname) else {
+                    let _ = conn.close();
+                    return WatchdogReport::default();
+                };
+                let mut report = WatchdogReport::default();
+                let mut abort_failed = false;
+                loop {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    let wait = if report.switched_to_postcopy {
+                        PROGRESS_INTERVAL
+                    } else if remaining.is_zero() {
+                        ABORT_RETRY_INTERVAL
+                    } else {
+                        remaining.min(PROGRESS_INTERVAL)
+                    };
+                    match finished.recv_timeout(wait) {
+                        // Once switched to post-copy the domain is already running on the target,
+                        // so libvirt refuses to abort the migration
+                        Err(RecvTimeoutError::Timeout)
+                            if !report.switched_to_postcopy && Instant::now() >= deadline =>
+                        {
+                            // Aborting fails if the job did not start yet, so keep trying until the
+                            // migration call returns
+                            match dom.abort_job() {
+                                Ok(_) => {
+                                    report.aborted = true;
+                                    break;
+                                }
+                                Err(e) if !abort_failed => {
+                                    println!("Failed to abort migration, retrying: {}", e);
+                                    abort_failed = true;
+                                }
+                                Err(_) => {}
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => {
+                            if postcopy_at.is_some_and(|at| Instant::now() >= at) {
+                                match dom.migrate_start_post_copy(0) {
+                                    Ok(_) => {
+                                        println!(
+                                            "Switched migration to post-copy, it can not be \
+                                             aborted anymore"
+                                        );
+                                        report.switched_to_postcopy = true;
+                                    }
+                                    Err(e) => println!("Failed to switch to post-copy: {}", e),
+                                }
+                                postcopy_at = None;
+                            }
+                            if let Ok(job_stats) = dom.get_job_stats(0) {
+                                println!(
+                                    "Migration progress: {}/{} bytes, {} bytes remaining",
+                                    job_stats.data_processed.unwrap_or_default(),
+                                    job_stats.data_total.unwrap_or_default(),
+                                    job_stats.data_remaining.unwrap_or_default()
+                                );
+                            }
+                        }
+                        _ => break,
+                    }
+                }
+                if let Err(e) = conn.close() {
+                    println!("Failed to disconnect from hypervisor: {}", e);
+                }
+                report
+            })
+        };
+        // Unlike migrate, this does not look the domain up again afterwards, which would fail
+        // since UNDEFINE_SOURCE already removed it here
+        let migration = dom.migrate_to_uri2(
+            dst_uri.as_deref(),
+            options.migration_uri.as_deref(),
+            None,
+            flags,
+            None,
+            options.max_bandwidth,
+        );
+        // Dropping the sender stops the watchdog
+        drop(migration_done);
+        let report = watchdog.join().unwrap_or_else(|_| {
+            println!("Migration watchdog panicked");
+            WatchdogReport::default()
+        });
+        if migration.is_ok() {
+            // The source domain is undefined after a successful migration, so there are no
+            // completed job stats to report here
             println!("Domain migrated");
-
-            if let Ok(job_stats) = dom.get_job_stats(sys::VIR_DOMAIN_JOB_STATS_COMPLETED) {
-                println!(
-                    "Migration completed in {}ms",
-                    job_stats
-                        .time_elapsed
-                        .map(|time| time.to_string())
-                        .unwrap_or("?".into())
-                );
+            outcome = MigrationOutcome::Migrated;
+        } else if report.aborted {
+            println!("Migration timed out after {:?} and was aborted", timeout);
+        } else if let Err(e) = migration {
+            println!("Migration failed: {}", e);
+            if report.switched_to_postcopy {
+                outcome = MigrationOutcome::FailedInPostCopy;
             }
         }
+    } else {
+        println!("Domain '{}' not found", dname);
+        outcome = MigrationOutcome::NotFound;
     }
 
     if let Err(e) = conn.close() {
         panic!("Failed to disconnect from hypervisor: {}", e);
     }
     println!("Disconnected from source hypervisor");
+    outcome
+}
+
+/// What the watchdog did to the migration it watched
+#[derive(Default)]
+struct WatchdogReport {
+    aborted: bool,
+    switched_to_postcopy: bool,
 }
 
 #[cfg(test)]
@@ -233,4 +567,13 @@ mod tests {
         tracing_subscriber::fmt::init();
         create_instance_with_image().await;
     }
+
+    #[test]
+    fn test_parse_secs() {
+        assert_eq!(parse_secs("20"), Ok(Duration::from_secs(20)));
+        assert_eq!(parse_secs(" 5\n"), Ok(Duration::from_secs(5)));
+        assert!(parse_secs("").is_err());
+        assert!(parse_secs("-1").is_err());
+        assert!(parse_secs("1.5").is_err());
+    }
 }